@@ -40,15 +40,62 @@ use syntax_pos::DUMMY_SP;
 const VTABLE_OFFSET: usize = 3;
 
 /// Extracts a method from a trait object's vtable, at the specified index.
+///
+/// `vtable_index` is always relative to `trait_ref`'s *own* methods: for a
+/// trait with no object-safe direct supertraits that's the same flattened
+/// position across `traits::supertraits` it has always been, since such
+/// traits still get the flat, fully-inlined layout; for a trait with at
+/// least one object-safe direct supertrait it's the position within
+/// `trait_ref`'s own methods only, since those are the only methods
+/// `get_vtable` inlines for it (supertrait methods are reached through
+/// `get_supertrait_vtable` instead). Either way, callers never add the
+/// supertrait-vtable-pointer prefix themselves — `supertrait_vtable_prefix_len`
+/// folds it in here.
 pub fn get_virtual_method<'blk, 'tcx>(bcx: Block<'blk, 'tcx>,
                                       llvtable: ValueRef,
+                                      trait_ref: ty::PolyTraitRef<'tcx>,
                                       vtable_index: usize)
                                       -> ValueRef {
+    let prefix = supertrait_vtable_prefix_len(bcx.ccx().tcx(), trait_ref);
+
     // Load the data pointer from the object.
-    debug!("get_virtual_method(vtable_index={}, llvtable={:?})",
-           vtable_index, Value(llvtable));
+    debug!("get_virtual_method(vtable_index={}, prefix={}, llvtable={:?})",
+           vtable_index, prefix, Value(llvtable));
 
-    Load(bcx, GEPi(bcx, llvtable, &[vtable_index + VTABLE_OFFSET]))
+    Load(bcx, GEPi(bcx, llvtable, &[VTABLE_OFFSET + prefix + vtable_index]))
+}
+
+/// Extracts the vtable of the `supertrait_index`'th entry in a trait
+/// object's supertrait-vtable-pointer prefix (see `get_vtable`), letting
+/// codegen turn a `&dyn Sub -> &dyn Super` upcast into a single load instead
+/// of rebuilding `Super`'s vtable from scratch. The prefix sits directly
+/// after the header, so `supertrait_index` needs nothing beyond
+/// `VTABLE_OFFSET` to become a slot index; it indexes the same order
+/// produced by `direct_object_safe_supertraits`.
+pub fn get_supertrait_vtable<'blk, 'tcx>(bcx: Block<'blk, 'tcx>,
+                                         llvtable: ValueRef,
+                                         supertrait_index: usize)
+                                         -> ValueRef {
+    debug!("get_supertrait_vtable(supertrait_index={}, llvtable={:?})",
+           supertrait_index, Value(llvtable));
+
+    Load(bcx, GEPi(bcx, llvtable, &[VTABLE_OFFSET + supertrait_index]))
+}
+
+/// Translates a `&dyn Sub -> &dyn Super` (or `&mut`/`Box`) trait-object
+/// upcast, where `Super` is one of `Sub`'s direct object-safe supertraits.
+/// Unsizing coercions never change the data half of a fat pointer, only
+/// the vtable half, so this is the entire upcast: swap `llvtable` for the
+/// `supertrait_index`'th entry of its supertrait-vtable-pointer prefix via
+/// `get_supertrait_vtable`, and hand `lldata` back untouched. Call sites
+/// translating an `Unsize` coercion between trait object types go through
+/// here rather than calling `get_supertrait_vtable` directly.
+pub fn trans_supertrait_upcast<'blk, 'tcx>(bcx: Block<'blk, 'tcx>,
+                                           lldata: ValueRef,
+                                           llvtable: ValueRef,
+                                           supertrait_index: usize)
+                                           -> (ValueRef, ValueRef) {
+    (lldata, get_supertrait_vtable(bcx, llvtable, supertrait_index))
 }
 
 /// Generate a shim function that allows an object type like `SomeTrait` to
@@ -74,6 +121,7 @@ pub fn get_virtual_method<'blk, 'tcx>(bcx: Block<'blk, 'tcx>,
 /// that go through this shim function.
 pub fn trans_object_shim<'a, 'tcx>(ccx: &'a CrateContext<'a, 'tcx>,
                                    method_ty: Ty<'tcx>,
+                                   trait_ref: ty::PolyTraitRef<'tcx>,
                                    vtable_index: usize)
                                    -> ValueRef {
     let _icx = push_ctxt("trans_object_shim");
@@ -83,6 +131,11 @@ pub fn trans_object_shim<'a, 'tcx>(ccx: &'a CrateContext<'a, 'tcx>,
            vtable_index,
            method_ty);
 
+    // `vtable_index` arrives relative to `trait_ref`'s own methods (see
+    // `get_virtual_method`); fold in the supertrait-vtable-pointer prefix
+    // here so the `Virtual` callee below carries the final vtable slot.
+    let vtable_index = supertrait_vtable_prefix_len(tcx, trait_ref) + vtable_index;
+
     let sig = tcx.erase_late_bound_regions(&method_ty.fn_sig());
     let sig = tcx.normalize_associated_type(&sig);
     let fn_ty = FnType::new(ccx, method_ty.fn_abi(), &sig, &[]);
@@ -116,12 +169,120 @@ pub fn trans_object_shim<'a, 'tcx>(ccx: &'a CrateContext<'a, 'tcx>,
     llfn
 }
 
+/// Resolves a single trait ref's own methods (not its supertraits') to the
+/// `ValueRef`s that go in its vtable, in `get_vtable_methods` order, with a
+/// null pointer standing in for methods that aren't vtable-safe or whose
+/// where-clauses don't hold. For the flat layout `get_vtable` calls this
+/// once per entry of `traits::supertraits` to build its method array; for
+/// the nested layout it calls it once, on `trait_ref` alone.
+fn trait_own_vtable_methods<'a, 'tcx>(ccx: &CrateContext<'a, 'tcx>,
+                                      trait_ref: ty::PolyTraitRef<'tcx>)
+                                      -> Vec<ValueRef> {
+    let tcx = ccx.tcx();
+    let vtable = fulfill_obligation(ccx.shared(), DUMMY_SP, trait_ref.clone());
+    match vtable {
+        // Should default trait error here?
+        traits::VtableDefaultImpl(_) |
+        traits::VtableBuiltin(_) => {
+            Vec::new()
+        }
+        traits::VtableImpl(
+            traits::VtableImplData {
+                impl_def_id: id,
+                substs,
+                nested: _ }) => {
+            let nullptr = C_null(Type::nil(ccx).ptr_to());
+            get_vtable_methods(tcx, id, substs)
+                .into_iter()
+                .map(|opt_mth| opt_mth.map_or(nullptr, |mth| {
+                    Callee::def(ccx, mth.method.def_id, &mth.substs).reify(ccx)
+                }))
+                .collect()
+        }
+        traits::VtableClosure(
+            traits::VtableClosureData {
+                closure_def_id,
+                substs,
+                nested: _ }) => {
+            let trait_closure_kind = tcx.lang_items.fn_trait_kind(trait_ref.def_id()).unwrap();
+            let llfn = closure::trans_closure_method(ccx,
+                                                     closure_def_id,
+                                                     substs,
+                                                     trait_closure_kind);
+            vec![llfn]
+        }
+        traits::VtableFnPointer(
+            traits::VtableFnPointerData {
+                fn_ty: bare_fn_ty,
+                nested: _ }) => {
+            let trait_closure_kind = tcx.lang_items.fn_trait_kind(trait_ref.def_id()).unwrap();
+            vec![trans_fn_pointer_shim(ccx, trait_closure_kind, bare_fn_ty)]
+        }
+        traits::VtableObject(ref data) => {
+            // this would imply that the Self type being erased is
+            // an object type; this cannot happen because we
+            // cannot cast an unsized type into a trait object
+            bug!("cannot get vtable for an object type: {:?}",
+                 data);
+        }
+        traits::VtableParam(..) => {
+            bug!("resolved vtable for {:?} to bad vtable {:?} in trans",
+                 trait_ref,
+                 vtable);
+        }
+    }
+}
+
+/// Returns `trait_ref`'s direct supertraits that are themselves object
+/// safe, in the order their vtable pointers are stored in the
+/// supertrait-pointer prefix of `trait_ref`'s vtable (see `get_vtable`).
+/// Supertraits that aren't object safe have no vtable of their own to
+/// point at, so they're left out entirely rather than taking up a null
+/// slot.
+fn direct_object_safe_supertraits<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>,
+                                            trait_ref: ty::PolyTraitRef<'tcx>)
+                                            -> Vec<ty::PolyTraitRef<'tcx>> {
+    tcx.lookup_super_predicates(trait_ref.def_id())
+        .predicates
+        .into_iter()
+        .filter_map(|predicate| predicate.to_opt_poly_trait_ref())
+        .map(|direct| direct.subst_supertrait(tcx, &trait_ref))
+        .filter(|direct| tcx.is_object_safe(direct.def_id()))
+        .collect()
+}
+
+/// The number of supertrait-vtable-pointer slots that precede `trait_ref`'s
+/// own methods in its vtable (see `get_vtable`): one per object-safe direct
+/// supertrait, `0` for traits that keep the flat, fully-inlined layout.
+/// `get_virtual_method`/`trans_object_shim` add this to every vtable index
+/// they're given, so callers never need to know or compute it themselves.
+/// Unlike the method `ValueRef`s themselves, this only walks `trait_ref`'s
+/// static supertrait predicates — no `fulfill_obligation` — so it's cheap
+/// enough to call on every virtual dispatch.
+pub fn supertrait_vtable_prefix_len<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>,
+                                              trait_ref: ty::PolyTraitRef<'tcx>)
+                                              -> usize {
+    direct_object_safe_supertraits(tcx, trait_ref).len()
+}
+
 /// Creates a returns a dynamic vtable for the given type and vtable origin.
 /// This is used only for objects.
 ///
 /// The `trait_ref` encodes the erased self type. Hence if we are
 /// making an object `Foo<Trait>` from a value of type `Foo<T>`, then
 /// `trait_ref` would map `T:Trait`.
+///
+/// Traits with no object-safe direct supertrait keep the flat layout this
+/// function has always produced: every supertrait method inlined into one
+/// array via `traits::supertraits(...).flat_map(...)`. Traits with at least
+/// one object-safe direct supertrait instead get the nested layout: a
+/// prefix of one pointer per such supertrait, to that supertrait's own
+/// vtable (built by a recursive call to this very function), followed by
+/// *only* `trait_ref`'s own methods — supertrait methods are reached
+/// through the supertrait's vtable pointer via `get_supertrait_vtable`
+/// rather than being duplicated here. `get_virtual_method`/
+/// `trans_object_shim` fold the prefix's length into every vtable index via
+/// `supertrait_vtable_prefix_len`, so indices stay correct either way.
 pub fn get_vtable<'a, 'tcx>(ccx: &CrateContext<'a, 'tcx>,
                             trait_ref: ty::PolyTraitRef<'tcx>)
                             -> ValueRef
@@ -138,61 +299,19 @@ pub fn get_vtable<'a, 'tcx>(ccx: &CrateContext<'a, 'tcx>,
     }
 
     // Not in the cache. Build it.
-    let methods = traits::supertraits(tcx, trait_ref.clone()).flat_map(|trait_ref| {
-        let vtable = fulfill_obligation(ccx.shared(), DUMMY_SP, trait_ref.clone());
-        match vtable {
-            // Should default trait error here?
-            traits::VtableDefaultImpl(_) |
-            traits::VtableBuiltin(_) => {
-                Vec::new().into_iter()
-            }
-            traits::VtableImpl(
-                traits::VtableImplData {
-                    impl_def_id: id,
-                    substs,
-                    nested: _ }) => {
-                let nullptr = C_null(Type::nil(ccx).ptr_to());
-                get_vtable_methods(tcx, id, substs)
-                    .into_iter()
-                    .map(|opt_mth| opt_mth.map_or(nullptr, |mth| {
-                        Callee::def(ccx, mth.method.def_id, &mth.substs).reify(ccx)
-                    }))
-                    .collect::<Vec<_>>()
-                    .into_iter()
-            }
-            traits::VtableClosure(
-                traits::VtableClosureData {
-                    closure_def_id,
-                    substs,
-                    nested: _ }) => {
-                let trait_closure_kind = tcx.lang_items.fn_trait_kind(trait_ref.def_id()).unwrap();
-                let llfn = closure::trans_closure_method(ccx,
-                                                         closure_def_id,
-                                                         substs,
-                                                         trait_closure_kind);
-                vec![llfn].into_iter()
-            }
-            traits::VtableFnPointer(
-                traits::VtableFnPointerData {
-                    fn_ty: bare_fn_ty,
-                    nested: _ }) => {
-                let trait_closure_kind = tcx.lang_items.fn_trait_kind(trait_ref.def_id()).unwrap();
-                vec![trans_fn_pointer_shim(ccx, trait_closure_kind, bare_fn_ty)].into_iter()
-            }
-            traits::VtableObject(ref data) => {
-                // this would imply that the Self type being erased is
-                // an object type; this cannot happen because we
-                // cannot cast an unsized type into a trait object
-                bug!("cannot get vtable for an object type: {:?}",
-                     data);
-            }
-            traits::VtableParam(..) => {
-                bug!("resolved vtable for {:?} to bad vtable {:?} in trans",
-                     trait_ref,
-                     vtable);
-            }
-        }
-    });
+    let nested_supertraits = direct_object_safe_supertraits(tcx, trait_ref.clone());
+
+    let supertrait_vtables: Vec<_> = nested_supertraits.iter()
+        .map(|supertrait| get_vtable(ccx, supertrait.clone()))
+        .collect();
+
+    let methods: Vec<_> = if nested_supertraits.is_empty() {
+        traits::supertraits(tcx, trait_ref.clone())
+            .flat_map(|supertrait| trait_own_vtable_methods(ccx, supertrait))
+            .collect()
+    } else {
+        trait_own_vtable_methods(ccx, trait_ref.clone())
+    };
 
     let size_ty = sizing_type_of(ccx, trait_ref.self_ty());
     let size = machine::llsize_of_alloc(ccx, size_ty);
@@ -203,16 +322,53 @@ pub fn get_vtable<'a, 'tcx>(ccx: &CrateContext<'a, 'tcx>,
         glue::get_drop_glue(ccx, trait_ref.self_ty()),
         C_uint(ccx, size),
         C_uint(ccx, align)
-    ].into_iter().chain(methods).collect();
+    ].into_iter().chain(supertrait_vtables).chain(methods).collect();
+
+    // Two distinct trait refs can still resolve to byte-identical vtables
+    // (same drop glue, size, align and method pointers), which is common in
+    // generic-heavy crates. Before emitting a fresh global, look it up by a
+    // structural key built from exactly the `ValueRef`s that go into the
+    // `C_struct` plus the `packed` flag, and reuse the existing symbol if one
+    // is already interned under that key. Using the raw `ValueRef`s (rather
+    // than, say, names or types) is what makes this safe: a null slot for a
+    // method that isn't vtable-safe or whose predicates don't hold is always
+    // a distinct `ValueRef` from any real method pointer, so differing null
+    // patterns can never hash the same.
+    let packed = false;
+    let content_key = VtableContentKey {
+        packed: packed,
+        components: components.clone(),
+    };
 
-    let vtable_const = C_struct(ccx, &components, false);
+    let cached = ccx.vtable_contents().borrow().get(&content_key).cloned();
+    if let Some(vtable) = cached {
+        ccx.vtables().borrow_mut().insert(trait_ref, vtable);
+        return vtable;
+    }
+
+    let vtable_const = C_struct(ccx, &components, packed);
     let align = machine::llalign_of_pref(ccx, val_ty(vtable_const));
     let vtable = consts::addr_of(ccx, vtable_const, align, "vtable");
 
+    ccx.vtable_contents().borrow_mut().insert(content_key, vtable);
     ccx.vtables().borrow_mut().insert(trait_ref, vtable);
     vtable
 }
 
+/// Structural key used to intern vtable constants by content rather than by
+/// the `PolyTraitRef` that produced them, in `ccx.vtable_contents()`. See
+/// `get_vtable`. That cache lives on `CrateContext` itself, alongside
+/// `vtables()`, rather than in a process-wide `thread_local!`: a
+/// `ValueRef` is only meaningful within the single LLVM module it was built
+/// in, and a thread can translate more than one `CrateContext` in sequence
+/// (e.g. one per codegen unit), so a thread-local cache would leak
+/// `ValueRef`s from one module's `vtable_contents()` lookups into the next.
+#[derive(PartialEq, Eq, Hash)]
+pub struct VtableContentKey {
+    packed: bool,
+    components: Vec<ValueRef>,
+}
+
 pub fn get_vtable_methods<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>,
                                     impl_id: DefId,
                                     substs: &'tcx Substs<'tcx>)